@@ -1,15 +1,82 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt;
-use std::path::PathBuf;
-
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use bytes::Bytes;
+use clap::Parser;
 use futures::{SinkExt, StreamExt, TryStreamExt};
 
+use rand::Rng;
+use reqwest::{Response as HttpResponse, StatusCode};
+
 use serde::de::DeserializeOwned;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::Semaphore;
 use tokio_util::codec::{BytesCodec, FramedWrite};
 
+/// Name of the ledger file, kept in the output directory.
+const LEDGER_FILE: &str = "ledger.jsonl";
+
+/// Initial delay before the first retry.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound on the computed backoff delay, regardless of attempt count.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Fetches and archives the photos posted to a Tumblr blog.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Identifier of the blog to fetch, e.g. `staff.tumblr.com`.
+    blog_identifier: String,
+
+    /// Directory photos are written into.
+    #[arg(short, long, default_value = "/tmp/pics")]
+    output: PathBuf,
+
+    /// Write photos into a single zip archive at this path instead of as
+    /// loose files under `--output`.
+    #[arg(long)]
+    zip: Option<PathBuf>,
+
+    /// Re-host photos to a pict-rs instance at this URL instead of writing
+    /// them locally, recording the hosted URLs in `manifest.json` under
+    /// `--output`.
+    #[arg(long)]
+    pict_rs_url: Option<String>,
+
+    /// Bearer token used to authenticate with `--pict-rs-url`.
+    #[arg(long)]
+    pict_rs_token: Option<String>,
+
+    /// Maximum number of photos to download concurrently.
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+
+    /// Maximum number of attempts (including the first) before giving up on
+    /// a request.
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    /// Maximum number of posts/photos to fetch.
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Tumblr API key. Falls back to the `IMAGR_TOKEN` environment variable.
+    #[arg(long)]
+    api_key: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(bound = "T: fmt::Debug + DeserializeOwned")]
 pub struct ResponseEnvelope<T>
@@ -46,7 +113,7 @@ struct Link {
 }
 
 #[derive(Debug, Deserialize)]
-struct Response {
+struct PostsResponse {
     posts: Vec<Post>,
     #[serde(rename = "_links")]
     links: Option<Links>,
@@ -76,11 +143,11 @@ impl Into<Vec<DownloadablePhoto>> for Post {
         self.photos
             .into_iter()
             .enumerate()
-            .map(|(index, photo)| {
-                let url = photo.original_size.url;
-                let ext = extension(&url);
-                let filename = filename(id, &slug, index, ext);
-                DownloadablePhoto { filename, url }
+            .map(|(index, photo)| DownloadablePhoto {
+                id,
+                slug: slug.clone(),
+                index,
+                url: photo.original_size.url,
             })
             .collect()
     }
@@ -88,15 +155,115 @@ impl Into<Vec<DownloadablePhoto>> for Post {
 
 #[derive(Debug)]
 struct DownloadablePhoto {
-    filename: String,
+    id: u64,
+    slug: String,
+    index: usize,
     url: String,
 }
 
-fn extension(url: &str) -> &str {
+/// A record of photos that have been downloaded, keyed by `(post_id,
+/// index)`. The in-memory half is always active, for every sink, and
+/// collapses duplicate posts returned across pagination pages within a
+/// single run. The file half is only opened for the directory sink (the zip
+/// sink starts a fresh archive each run, and the pict-rs sink resumes from
+/// its own manifest instead): it's seeded from [`LEDGER_FILE`] at startup so
+/// re-running against the same output directory is incremental, and grows
+/// as photos are downloaded.
+struct Ledger {
+    seen: Mutex<HashSet<(u64, usize)>>,
+    file: Option<AsyncMutex<tokio::fs::File>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LedgerEntry {
+    post_id: u64,
+    index: usize,
+    url: String,
+    filename: String,
+}
+
+impl Ledger {
+    /// An in-memory-only ledger, for sinks that don't resume across runs.
+    /// Still collapses duplicate posts seen across pagination pages within
+    /// the run.
+    fn in_memory() -> Self {
+        Self {
+            seen: Mutex::new(HashSet::new()),
+            file: None,
+        }
+    }
+
+    /// Loads existing entries from `directory`/[`LEDGER_FILE`], if any, and
+    /// opens the file for appending new ones.
+    async fn open(directory: &Path) -> anyhow::Result<Self> {
+        let path = directory.join(LEDGER_FILE);
+
+        let mut seen = HashSet::new();
+        if let Ok(contents) = tokio::fs::read_to_string(&path).await {
+            for line in contents.lines() {
+                let entry: LedgerEntry = serde_json::from_str(line)?;
+                seen.insert((entry.post_id, entry.index));
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        Ok(Self {
+            seen: Mutex::new(seen),
+            file: Some(AsyncMutex::new(file)),
+        })
+    }
+
+    /// Marks `(post_id, index)` as seen, returning `true` if it wasn't
+    /// already recorded. Callers should skip photos for which this returns
+    /// `false`.
+    fn mark_new(&self, post_id: u64, index: usize) -> bool {
+        self.seen.lock().unwrap().insert((post_id, index))
+    }
+
+    /// Appends a successfully-downloaded entry to the ledger file, if one is
+    /// open; a no-op for sinks with only an in-memory ledger.
+    async fn record(&self, entry: &LedgerEntry) -> anyhow::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let mut file = file.lock().await;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Falls back to the extension found on the URL itself when the
+/// `Content-Type` response header doesn't map to a known image format.
+fn url_extension(url: &str) -> &str {
     let filename = url.rsplit('/').next().expect("wtf, this has no path?");
     filename.rsplit('.').next().unwrap_or("unknown")
 }
 
+/// Maps an image MIME type to its canonical file extension, e.g.
+/// `image/jpeg` -> `jpg`. Returns `None` for an image type we don't
+/// recognize, in which case the caller should fall back to the
+/// URL-derived extension.
+fn extension_for_content_type(content_type: &str) -> Option<&'static str> {
+    match content_type {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        _ => None,
+    }
+}
+
 fn filename(id: u64, slug: &str, index: usize, ext: &str) -> String {
     let slug_dash = if slug == "" { "" } else { "-" };
     format!(
@@ -109,26 +276,167 @@ fn filename(id: u64, slug: &str, index: usize, ext: &str) -> String {
     )
 }
 
+/// Fetches `url`, retrying on connection errors, timeouts, and retryable
+/// status codes (429 and 5xx) with exponential backoff. See [`with_retry`]
+/// for the backoff policy.
+async fn get_with_retry(url: &str, max_attempts: u32) -> anyhow::Result<HttpResponse> {
+    with_retry(max_attempts, || reqwest::get(url)).await
+}
+
+/// POSTs `bytes` as a multipart `images[]` field to `url`, retrying with the
+/// same policy as [`get_with_retry`]. Rebuilds the multipart body on every
+/// attempt, since a `reqwest::multipart::Form` can't be reused once sent.
+async fn post_multipart_with_retry(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+    filename: &str,
+    content_type: &str,
+    bytes: &Bytes,
+    max_attempts: u32,
+) -> anyhow::Result<HttpResponse> {
+    with_retry(max_attempts, || async {
+        // `content_type` only passed the cheap `starts_with("image/")` check
+        // upstream, so a malformed value from a misbehaving remote server can
+        // still fail `Mime` parsing here; propagate that instead of panicking.
+        let part = reqwest::multipart::Part::bytes(bytes.to_vec())
+            .file_name(filename.to_string())
+            .mime_str(content_type)?;
+        let form = reqwest::multipart::Form::new().part("images[]", part);
+
+        let mut request = client.post(url).multipart(form);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+
+        request.send().await
+    })
+    .await
+}
+
+/// Retries `make_request` with exponential backoff on connection errors,
+/// timeouts, and retryable status codes (429 and 5xx).
+///
+/// The delay starts at [`INITIAL_BACKOFF`], doubles on each failed attempt up
+/// to [`MAX_BACKOFF`], and has a small jitter added to avoid a thundering
+/// herd across concurrent requests. A `Retry-After` header on a 429 or 503
+/// response overrides the computed delay. Gives up and propagates the error
+/// once `max_attempts` have been made. `max_attempts` is clamped to at least
+/// 1, so `--retries 0` still makes a single attempt instead of giving up
+/// before trying.
+async fn with_retry<F, Fut>(max_attempts: u32, mut make_request: F) -> anyhow::Result<HttpResponse>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = reqwest::Result<HttpResponse>>,
+{
+    let max_attempts = max_attempts.max(1);
+    let mut delay = INITIAL_BACKOFF;
+
+    for attempt in 1..=max_attempts {
+        let result = make_request().await;
+
+        let retry_after = match &result {
+            Ok(response) if is_retryable_status(response.status()) => {
+                retry_after(response).or(Some(delay))
+            }
+            Err(err) if is_retryable_error(err) => Some(delay),
+            _ => None,
+        };
+
+        match retry_after {
+            Some(_) if attempt == max_attempts => {
+                return match result {
+                    Ok(response) => Err(response.error_for_status().unwrap_err().into()),
+                    Err(err) => Err(err.into()),
+                };
+            }
+            Some(sleep_for) => {
+                tokio::time::sleep(jittered(sleep_for)).await;
+                delay = (delay * 2).min(MAX_BACKOFF);
+            }
+            None => return Ok(result?),
+        }
+    }
+
+    unreachable!("loop either returns or propagates by the last attempt")
+}
+
+/// Whether a status code is worth retrying: 429 or any 5xx.
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Whether a transport-level error is worth retrying: connection failures and
+/// timeouts, as opposed to e.g. URL parse errors.
+fn is_retryable_error(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout()
+}
+
+/// Parses a `Retry-After` header off a 429/503 response, in either its
+/// delta-seconds form (`Retry-After: 120`) or HTTP-date form
+/// (`Retry-After: Fri, 31 Dec 1999 23:59:59 GMT`).
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    if !matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    httpdate::parse_http_date(value)
+        .ok()?
+        .duration_since(SystemTime::now())
+        .ok()
+}
+
+/// Adds up to 20% random jitter to a backoff delay.
+fn jittered(delay: Duration) -> Duration {
+    let jitter = rand::thread_rng().gen_range(0.0..0.2);
+    delay.mul_f64(1.0 + jitter)
+}
+
 async fn fetch_photo_posts(
     tx: Sender<DownloadablePhoto>,
     blog_identifier: String,
     api_key: String,
+    retries: u32,
+    limit: Option<usize>,
+    ledger: Arc<Ledger>,
 ) -> anyhow::Result<()> {
     let url = format!(
         "https://api.tumblr.com/v2/blog/{}/posts/photo?api_key={}",
         blog_identifier, api_key
     );
 
-    let mut envelope = reqwest::get(url)
+    let mut envelope = get_with_retry(&url, retries)
         .await?
-        .json::<ResponseEnvelope<Response>>()
+        .json::<ResponseEnvelope<PostsResponse>>()
         .await?;
 
-    loop {
+    let mut sent = 0;
+
+    'pages: loop {
         for post in envelope.response.posts {
             let photos: Vec<DownloadablePhoto> = post.into();
             for photo in photos {
+                if limit.is_some_and(|limit| sent >= limit) {
+                    break 'pages;
+                }
+                if !ledger.mark_new(photo.id, photo.index) {
+                    continue;
+                }
                 tx.send(photo).await?;
+                sent += 1;
             }
         }
 
@@ -137,9 +445,9 @@ async fn fetch_photo_posts(
                 "https://api.tumblr.com{}&api_key={}",
                 links.next.href, api_key
             );
-            envelope = reqwest::get(url)
+            envelope = get_with_retry(&url, retries)
                 .await?
-                .json::<ResponseEnvelope<Response>>()
+                .json::<ResponseEnvelope<PostsResponse>>()
                 .await?;
         } else {
             break;
@@ -149,13 +457,155 @@ async fn fetch_photo_posts(
     Ok(())
 }
 
-async fn fetch_and_write_photo(path: PathBuf, url: String) -> anyhow::Result<()> {
-    let (file, response) = tokio::join!(tokio::fs::File::create(path), reqwest::get(url));
+async fn fetch_and_write_photo(
+    photo: DownloadablePhoto,
+    sink: OutputSink,
+    semaphore: Arc<Semaphore>,
+    retries: u32,
+    ledger: Arc<Ledger>,
+) -> anyhow::Result<()> {
+    let _permit = semaphore.acquire_owned().await?;
+
+    let response = get_with_retry(&photo.url, retries).await?;
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    if !content_type.starts_with("image/") {
+        eprintln!(
+            "skipping {}: not an image (Content-Type: {})",
+            photo.url, content_type
+        );
+        return Ok(());
+    }
 
-    let file = file?;
-    let response = response?;
+    let ext =
+        extension_for_content_type(&content_type).unwrap_or_else(|| url_extension(&photo.url));
+    let filename = filename(photo.id, &photo.slug, photo.index, ext);
 
-    let framed_write = FramedWrite::new(file, BytesCodec::new());
+    if sink.should_skip(&filename, &photo.url).await? {
+        return Ok(());
+    }
+
+    sink.write(&photo.url, &filename, &content_type, response, retries)
+        .await?;
+
+    ledger
+        .record(&LedgerEntry {
+            post_id: photo.id,
+            index: photo.index,
+            url: photo.url,
+            filename,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the sibling `.tmp` path a download is streamed into before being
+/// renamed onto its final path.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Where a downloaded photo's bytes ultimately end up: loose files in a
+/// directory, entries in a single zip archive, or re-hosted on a pict-rs
+/// instance.
+///
+/// Cheap to clone: the directory variant just clones a path, the zip variant
+/// clones an `mpsc::Sender` handle to the archive writer task (which
+/// serializes entries one at a time since zip central-directory writing is
+/// inherently serial), and the pict-rs variant clones an `Arc`.
+#[derive(Clone)]
+enum OutputSink {
+    Directory(PathBuf),
+    Zip(Sender<ZipEntry>),
+    PictRs(Arc<PictRsSink>),
+}
+
+/// A photo's bytes en route to the zip writer task, over the sink channel.
+struct ZipEntry {
+    filename: String,
+    bytes: Bytes,
+}
+
+impl OutputSink {
+    /// Whether `filename` (or, for the pict-rs sink, `url`) has already been
+    /// written, so the caller can skip re-downloading it. Always `false` for
+    /// the zip sink, since each run starts a fresh archive.
+    async fn should_skip(&self, filename: &str, url: &str) -> anyhow::Result<bool> {
+        match self {
+            OutputSink::Directory(directory) => {
+                Ok(tokio::fs::try_exists(directory.join(filename)).await?)
+            }
+            OutputSink::Zip(_) => Ok(false),
+            OutputSink::PictRs(pict_rs) => Ok(pict_rs.manifest.lock().await.contains_key(url)),
+        }
+    }
+
+    /// Writes `response`'s body, downloaded from `url`, under `filename`.
+    ///
+    /// The directory sink streams straight into a sibling temp file, so a
+    /// large original never sits fully in memory. The zip and pict-rs sinks
+    /// have to buffer the whole body regardless: a zip entry's length is
+    /// written into its local header before the data, and a multipart form
+    /// needs the complete part up front, so neither can be streamed through
+    /// in a single pass.
+    async fn write(
+        &self,
+        url: &str,
+        filename: &str,
+        content_type: &str,
+        response: HttpResponse,
+        retries: u32,
+    ) -> anyhow::Result<()> {
+        match self {
+            OutputSink::Directory(directory) => {
+                let path = directory.join(filename);
+                let tmp_path = tmp_path(&path);
+
+                let result = stream_to_file(&tmp_path, response).await;
+                match result {
+                    Ok(()) => {
+                        tokio::fs::rename(&tmp_path, &path).await?;
+                        Ok(())
+                    }
+                    Err(err) => {
+                        let _ = tokio::fs::remove_file(&tmp_path).await;
+                        Err(err)
+                    }
+                }
+            }
+            OutputSink::Zip(tx) => {
+                let bytes = response.bytes().await?;
+                tx.send(ZipEntry {
+                    filename: filename.to_string(),
+                    bytes,
+                })
+                .await
+                .map_err(|_| anyhow::anyhow!("zip writer task is no longer running"))
+            }
+            OutputSink::PictRs(pict_rs) => {
+                let bytes = response.bytes().await?;
+                pict_rs
+                    .upload(url, filename, content_type, &bytes, retries)
+                    .await
+            }
+        }
+    }
+}
+
+/// Streams `response`'s body into `path`, flushing once the stream is
+/// exhausted.
+async fn stream_to_file(path: &Path, response: HttpResponse) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let framed_write = FramedWrite::new(&mut file, BytesCodec::new());
 
     let bytes = response.bytes_stream();
     bytes
@@ -163,22 +613,133 @@ async fn fetch_and_write_photo(path: PathBuf, url: String) -> anyhow::Result<()>
         .forward(framed_write.sink_map_err(anyhow::Error::from))
         .await?;
 
+    file.flush().await?;
+
+    Ok(())
+}
+
+/// Receives downloaded photos over `rx` and appends each as an entry in a
+/// zip archive at `path`, one at a time, finalizing the archive once the
+/// channel closes.
+async fn run_zip_sink(path: PathBuf, mut rx: Receiver<ZipEntry>) -> anyhow::Result<()> {
+    let file = tokio::fs::File::create(&path).await?;
+    let mut writer = ZipFileWriter::with_tokio(file);
+
+    while let Some(entry) = rx.recv().await {
+        let builder = ZipEntryBuilder::new(entry.filename.into(), Compression::Deflate);
+        writer.write_entry_whole(builder, &entry.bytes).await?;
+    }
+
+    writer.close().await?;
+
     Ok(())
 }
 
+/// Re-hosts photos on a pict-rs instance via multipart upload, recording a
+/// mapping from original Tumblr URL to hosted URL in a manifest file.
+struct PictRsSink {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    manifest_path: PathBuf,
+    manifest: AsyncMutex<HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PictRsResponse {
+    files: Vec<PictRsFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PictRsFile {
+    file: String,
+}
+
+impl PictRsSink {
+    async fn open(
+        url: String,
+        token: Option<String>,
+        manifest_path: PathBuf,
+    ) -> anyhow::Result<Self> {
+        let manifest = match tokio::fs::read_to_string(&manifest_path).await {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url,
+            token,
+            manifest_path,
+            manifest: AsyncMutex::new(manifest),
+        })
+    }
+
+    async fn upload(
+        &self,
+        original_url: &str,
+        filename: &str,
+        content_type: &str,
+        bytes: &Bytes,
+        retries: u32,
+    ) -> anyhow::Result<()> {
+        let response = post_multipart_with_retry(
+            &self.client,
+            &self.url,
+            self.token.as_deref(),
+            filename,
+            content_type,
+            bytes,
+            retries,
+        )
+        .await?
+        .json::<PictRsResponse>()
+        .await?;
+
+        let file = response
+            .files
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("pict-rs response for {filename} had no files"))?;
+        let hosted_url = format!(
+            "{}/image/original/{}",
+            self.url.trim_end_matches('/'),
+            file.file
+        );
+
+        let mut manifest = self.manifest.lock().await;
+        manifest.insert(original_url.to_string(), hosted_url);
+        let json = serde_json::to_string_pretty(&*manifest)?;
+        tokio::fs::write(&self.manifest_path, json).await?;
+
+        Ok(())
+    }
+}
+
 async fn fetch_photos(
     mut rx: Receiver<DownloadablePhoto>,
-    directory: PathBuf,
+    sink: OutputSink,
+    max_concurrency: usize,
+    retries: u32,
+    ledger: Arc<Ledger>,
 ) -> anyhow::Result<()> {
-    tokio::fs::create_dir_all(&directory).await?;
+    if let OutputSink::Directory(directory) = &sink {
+        tokio::fs::create_dir_all(directory).await?;
+    }
+    let semaphore = Arc::new(Semaphore::new(max_concurrency));
     let mut tasks = futures::stream::FuturesUnordered::new();
 
     loop {
         tokio::select! {
              photo_opt = rx.recv() => {
                  if let Some(photo) = photo_opt {
-                     let path = directory.join(photo.filename);
-                     tasks.push(fetch_and_write_photo(path, photo.url));
+                     tasks.push(fetch_and_write_photo(
+                         photo,
+                         sink.clone(),
+                         semaphore.clone(),
+                         retries,
+                         ledger.clone(),
+                     ));
                  } else {
                      break;
                  }
@@ -198,20 +759,55 @@ async fn fetch_photos(
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // TODO: Why is
-    // https://thingsonhazelshead.tumblr.com/post/174913738196/we-expect-to-be-in-control-of-our-internet
-    // duplicated?
-    let blog_identifier = "thingsonhazelshead.tumblr.com".to_string();
-    let download_directory = PathBuf::from("/tmp/pics");
-
-    let api_key = env::var("IMAGR_TOKEN")?;
+    let args = Args::parse();
+
+    let api_key = args
+        .api_key
+        .or_else(|| env::var("IMAGR_TOKEN").ok())
+        .ok_or_else(|| anyhow::anyhow!("no API key given; pass --api-key or set IMAGR_TOKEN"))?;
+
+    tokio::fs::create_dir_all(&args.output).await?;
+
+    let (sink, join_zip) = if let Some(pict_rs_url) = args.pict_rs_url {
+        let manifest_path = args.output.join("manifest.json");
+        let pict_rs = PictRsSink::open(pict_rs_url, args.pict_rs_token, manifest_path).await?;
+        (OutputSink::PictRs(Arc::new(pict_rs)), None)
+    } else if let Some(zip_path) = args.zip {
+        let (zip_tx, zip_rx) = mpsc::channel(64);
+        let join_zip = tokio::spawn(run_zip_sink(zip_path, zip_rx));
+        (OutputSink::Zip(zip_tx), Some(join_zip))
+    } else {
+        (OutputSink::Directory(args.output.clone()), None)
+    };
+
+    // Every sink gets a ledger, since every sink needs to collapse duplicate
+    // posts Tumblr returns across pagination pages within the run. Only the
+    // directory sink resumes across runs through the ledger, though (the
+    // pict-rs sink uses its own `manifest.json`), so only it gets one backed
+    // by a file: a zip or pict-rs run against an `--output` previously used
+    // for a directory run must not have its photos silently dropped by that
+    // run's `ledger.jsonl`.
+    let ledger = Arc::new(match &sink {
+        OutputSink::Directory(directory) => Ledger::open(directory).await?,
+        OutputSink::Zip(_) | OutputSink::PictRs(_) => Ledger::in_memory(),
+    });
 
     let (tx, rx) = mpsc::channel(64);
 
-    let join_fetch_posts = tokio::spawn(fetch_photo_posts(tx, blog_identifier, api_key.clone()));
-    fetch_photos(rx, download_directory).await?;
+    let join_fetch_posts = tokio::spawn(fetch_photo_posts(
+        tx,
+        args.blog_identifier,
+        api_key,
+        args.retries,
+        args.limit,
+        ledger.clone(),
+    ));
+    fetch_photos(rx, sink, args.concurrency, args.retries, ledger).await?;
 
     join_fetch_posts.await??;
+    if let Some(join_zip) = join_zip {
+        join_zip.await??;
+    }
 
     Ok(())
 }